@@ -0,0 +1,155 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Max recorded frames per sample. Also sizes `StackBuffer`, the scratch
+/// space the signal handler unwinds into.
+pub const MAX_DEPTH: usize = 128;
+
+/// Fixed-size buffer the signal handler unwinds a raw address stack into.
+/// Pre-allocated once on the `Profiler` and reused by every sample, so
+/// recording a stack never allocates.
+pub type StackBuffer = [u64; MAX_DEPTH];
+
+/// Number of slots in `Collector`'s fixed table. Sized to make steady-state
+/// sampling of a typical run hit an existing slot rather than ever filling
+/// the table.
+const CAPACITY: usize = 4096;
+
+type Slot = Option<((Vec<u64>, Vec<String>), isize)>;
+
+/// Aggregates raw, unsymbolized address stacks by occurrence count, keyed
+/// together with whatever named regions (see `enter_region`) were active
+/// when the sample was taken.
+///
+/// Modeled on pprof-rs's `Collector`: safe to drive from a signal handler.
+/// Unlike a `HashMap`, `record` never has to build an owned key just to
+/// probe the table — it hashes and compares the caller's borrowed
+/// `stack`/`regions` slices directly against whatever is already stored, so
+/// a repeat occurrence of a hot stack only ever reads, never allocates.
+/// Allocation happens exactly once per distinct (stack, regions) shape: the
+/// first time it's seen, when there's nothing to compare against yet and a
+/// slot has to be filled in.
+///
+/// The table is a fixed-size, linearly-probed array rather than a `HashMap`
+/// that could rehash/resize (and thus allocate) under the signal handler.
+/// If every slot is taken by other distinct stacks, a brand new shape is
+/// dropped rather than growing the table — losing a sample is preferable to
+/// an unbounded or reallocating signal handler.
+pub struct Collector {
+    slots: Vec<Slot>,
+    len: usize,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Collector {
+            slots: vec![None; CAPACITY],
+            len: 0,
+        }
+    }
+}
+
+fn hash_sample(stack: &[u64], regions: &[String]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    stack.hash(&mut hasher);
+    regions.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Collector {
+    /// Records one occurrence of `stack`/`regions`. Allocation-free for any
+    /// shape already present in the table; allocates only to copy in a
+    /// genuinely new shape, and drops the sample if the table is full.
+    pub fn record(&mut self, stack: &[u64], regions: &[String]) {
+        let mut idx = (hash_sample(stack, regions) as usize) % CAPACITY;
+        for _ in 0..CAPACITY {
+            match &mut self.slots[idx] {
+                Some((key, count)) if key.0 == stack && key.1 == regions => {
+                    *count += 1;
+                    return;
+                }
+                Some(_) => idx = (idx + 1) % CAPACITY,
+                None => {
+                    self.slots[idx] = Some(((stack.to_vec(), regions.to_vec()), 1));
+                    self.len += 1;
+                    return;
+                }
+            }
+        }
+        // Table full: every slot probed belongs to some other distinct
+        // stack. Drop this sample rather than growing the table.
+    }
+
+    /// Consumes the collector, yielding each unique (stack, regions) pair
+    /// with its sample count.
+    pub fn into_samples(self) -> impl Iterator<Item = (Vec<u64>, Vec<String>, isize)> {
+        self.slots
+            .into_iter()
+            .flatten()
+            .map(|((stack, regions), count)| (stack, regions, count))
+    }
+
+    /// Clones out the samples collected so far without resetting the
+    /// collector, so a `ProfilerGuard` can report progress before it drops.
+    pub fn snapshot(&self) -> Vec<(Vec<u64>, Vec<String>, isize)> {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.as_ref())
+            .map(|((stack, regions), count)| (stack.clone(), regions.clone(), *count))
+            .collect()
+    }
+
+    /// Number of distinct (stack, regions) shapes recorded so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_aggregates_repeat_stacks() {
+        let mut collector = Collector::default();
+        collector.record(&[1, 2, 3], &[]);
+        collector.record(&[1, 2, 3], &[]);
+        collector.record(&[4, 5, 6], &[]);
+
+        assert_eq!(collector.len(), 2);
+        let samples = collector.snapshot();
+        let count_of = |stack: &[u64]| {
+            samples
+                .iter()
+                .find(|(s, _, _)| s == stack)
+                .map(|(_, _, count)| *count)
+                .unwrap()
+        };
+        assert_eq!(count_of(&[1, 2, 3]), 2);
+        assert_eq!(count_of(&[4, 5, 6]), 1);
+    }
+
+    #[test]
+    fn record_keeps_same_stack_different_regions_distinct() {
+        let mut collector = Collector::default();
+        collector.record(&[1, 2, 3], &["a".to_string()]);
+        collector.record(&[1, 2, 3], &["b".to_string()]);
+
+        assert_eq!(collector.len(), 2);
+    }
+
+    #[test]
+    fn into_samples_yields_every_recorded_shape() {
+        let mut collector = Collector::default();
+        collector.record(&[1], &[]);
+        collector.record(&[2], &[]);
+
+        let mut stacks: Vec<_> = collector.into_samples().map(|(s, _, _)| s).collect();
+        stacks.sort();
+        assert_eq!(stacks, vec![vec![1], vec![2]]);
+    }
+}
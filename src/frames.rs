@@ -0,0 +1,187 @@
+use crate::protos;
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Symbol {
+    pub name: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// A single sample: the full call stack (innermost frame first), plus the
+/// names of any user-defined regions (see `enter_region`) that were active
+/// when the sample was taken.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Frame {
+    pub stacks: Vec<Symbol>,
+    pub regions: Vec<String>,
+}
+
+/// Aggregates recorded `Frame`s by occurrence count, ready to be rendered
+/// into a pprof profile.
+#[derive(Default)]
+pub struct Report {
+    pub data: HashMap<Frame, isize>,
+}
+
+impl Report {
+    pub fn record(&mut self, frame: &Frame) {
+        *self.data.entry(frame.clone()).or_insert(0) += 1;
+    }
+
+    pub fn pprof(&self, duration: Duration) -> Result<protos::Profile, String> {
+        let mut strings = vec!["".to_string()];
+        let mut string_ids = HashMap::new();
+        string_ids.insert("".to_string(), 0i64);
+
+        let mut intern = |strings: &mut Vec<String>, s: String| -> i64 {
+            if let Some(id) = string_ids.get(&s) {
+                return *id;
+            }
+            let id = strings.len() as i64;
+            strings.push(s.clone());
+            string_ids.insert(s, id);
+            id
+        };
+
+        let samples_type_name = intern(&mut strings, "samples".to_string());
+        let count_unit = intern(&mut strings, "count".to_string());
+        let region_key = intern(&mut strings, "region".to_string());
+
+        let mut functions = HashMap::new();
+        let mut locations = HashMap::new();
+        let mut profile = protos::Profile::new();
+
+        for (frame, count) in self.data.iter() {
+            let mut sample = protos::Sample::new();
+            sample.value.push(*count as i64);
+
+            for symbol in frame.stacks.iter() {
+                let name = symbol.name.clone().unwrap_or_else(|| "??".to_string());
+                let location_id = *locations.entry(symbol.clone()).or_insert_with(|| {
+                    let id = profile.location.len() as u64 + 1;
+                    let function_id = *functions.entry(name.clone()).or_insert_with(|| {
+                        let id = profile.function.len() as u64 + 1;
+                        let mut function = protos::Function::new();
+                        function.id = id;
+                        function.name = intern(&mut strings, name.clone());
+                        function.system_name = function.name;
+                        function.filename =
+                            intern(&mut strings, symbol.file.clone().unwrap_or_default());
+                        profile.function.push(function);
+                        id
+                    });
+
+                    let mut line = protos::Line::new();
+                    line.function_id = function_id;
+                    line.line = symbol.line.unwrap_or(0) as i64;
+
+                    let mut location = protos::Location::new();
+                    location.id = id;
+                    location.line.push(line);
+                    profile.location.push(location);
+                    id
+                });
+                sample.location_id.push(location_id);
+            }
+
+            for region in frame.regions.iter() {
+                let mut label = protos::Label::new();
+                label.key = region_key;
+                label.str = intern(&mut strings, region.clone());
+                sample.label.push(label);
+            }
+
+            profile.sample.push(sample);
+        }
+
+        let mut sample_type = protos::ValueType::new();
+        sample_type.type_ = samples_type_name;
+        sample_type.unit = count_unit;
+        profile.sample_type.push(sample_type);
+
+        profile.string_table = strings;
+        profile.duration_nanos = duration.as_nanos() as i64;
+
+        Ok(profile)
+    }
+
+    /// Renders a folded-stack flamegraph SVG, as an alternative to `pprof`
+    /// for the common "just show me where time went" case. Each recorded
+    /// `Frame` collapses to one `region;region;func;func;func count` line
+    /// before handing off to inferno's folded-stack renderer — active
+    /// regions (see `enter_region`) come first as the outermost frames,
+    /// ahead of the real call stack, so the same annotations that show up
+    /// as pprof sample labels are visible here too.
+    pub fn flamegraph<W: io::Write>(&self, writer: W) -> Result<(), String> {
+        let lines = self.folded_lines();
+        let mut options = inferno::flamegraph::Options::default();
+        inferno::flamegraph::from_lines(
+            &mut options,
+            lines.iter().map(|line| line.as_str()),
+            writer,
+        )
+        .map_err(|e| format!("flamegraph render error: {}", e))
+    }
+
+    fn folded_lines(&self) -> Vec<String> {
+        self.data
+            .iter()
+            .map(|(frame, count)| {
+                let stack = frame
+                    .regions
+                    .iter()
+                    .cloned()
+                    .chain(
+                        frame
+                            .stacks
+                            .iter()
+                            .rev()
+                            .map(|symbol| symbol.name.clone().unwrap_or_else(|| "??".to_string())),
+                    )
+                    .collect::<Vec<_>>()
+                    .join(";");
+                format!("{} {}", stack, count)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str) -> Symbol {
+        Symbol {
+            name: Some(name.to_string()),
+            file: None,
+            line: None,
+        }
+    }
+
+    #[test]
+    fn folded_lines_puts_regions_ahead_of_the_reversed_stack() {
+        let mut report = Report::default();
+        report.record(&Frame {
+            stacks: vec![symbol("inner"), symbol("outer")],
+            regions: vec!["request".to_string()],
+        });
+
+        let lines = report.folded_lines();
+        assert_eq!(lines, vec!["request;outer;inner 1".to_string()]);
+    }
+
+    #[test]
+    fn folded_lines_without_regions_is_just_the_reversed_stack() {
+        let mut report = Report::default();
+        report.record(&Frame {
+            stacks: vec![symbol("inner"), symbol("outer")],
+            regions: vec![],
+        });
+
+        let lines = report.folded_lines();
+        assert_eq!(lines, vec!["outer;inner 1".to_string()]);
+    }
+}
@@ -1,3 +1,4 @@
+mod collector;
 mod frames;
 mod protos;
 mod timer;
@@ -6,6 +7,7 @@ mod timer;
 extern crate lazy_static;
 
 use crate::{
+    collector::{Collector, StackBuffer, MAX_DEPTH},
     frames::{Frame, Report, Symbol},
     timer::Timer,
 };
@@ -27,29 +29,60 @@ type Addr2LineEndianReader =
     addr2line::gimli::EndianReader<addr2line::gimli::RunTimeEndian, Arc<[u8]>>;
 type Addr2LineContext = addr2line::Context<Addr2LineEndianReader>;
 type Addr2LineFrameIter<'a> = addr2line::FrameIter<'a, Addr2LineEndianReader>;
+type Addr2LineUnwindContext = addr2line::gimli::UnwindContext<Addr2LineEndianReader>;
 
 struct DebugContext {
     addr_context: Addr2LineContext,
     debug_frame: addr2line::gimli::DebugFrame<Addr2LineEndianReader>,
 }
 
+/// Selects how `stop_profiler` serializes the collected `Report` to `fname`.
+pub enum OutputFormat {
+    /// pprof protobuf, consumable by `go tool pprof` and friends.
+    Pprof,
+    /// A folded-stack flamegraph SVG, rendered directly with no external
+    /// tool needed.
+    Flamegraph,
+}
+
+// RISC-V DWARF register numbers (see the RISC-V ELF psABI): x1 is the
+// return address and x2 is the stack pointer, which doubles as the usual
+// CFA base.
+const RISCV_DWARF_RA: u16 = 1;
+const RISCV_DWARF_SP: u16 = 2;
+
+// RISC-V has 32 general-purpose registers; sizes the fixed register
+// scratch space the signal handler unwinds with.
+const GP_REGISTERS: usize = 32;
+
 struct Profiler {
     fname: String,
+    format: OutputFormat,
     machine: usize,
     context: DebugContext,
     // Drop behavior is enough for timer
     #[allow(dead_code)]
     timer: Timer,
-    report: Report,
+    collector: Collector,
+    // Scratch space the signal handler unwinds into. Pre-allocated and
+    // reused by every sample, together with `unwind_ctx` and
+    // `unwind_registers` below, so unwinding a stack never allocates.
+    scratch: StackBuffer,
+    unwind_ctx: Addr2LineUnwindContext,
+    unwind_registers: [u64; GP_REGISTERS],
+    // Stack of names pushed/popped by `enter_region`/`RegionGuard::drop`;
+    // snapshotted into every sample taken while a region is active.
+    regions: Vec<String>,
 }
 
-// A temporary work till frame is properly implemented
-fn extract_frame(pc: u64, context: &DebugContext) -> Frame {
+// Symbolizes a single `pc` into a `Symbol`. Called once per frame recovered
+// by `unwind_stack`, so the full call chain ends up as one `Symbol` per
+// entry in `Frame.stacks`.
+fn extract_symbol(pc: u64, context: &DebugContext) -> Symbol {
     let addr_context = &context.addr_context;
     let mut file = None;
     let mut line = None;
 
-    // TODO: trace frame to reveal the whole stack
     let loc = addr_context.find_location(pc).unwrap();
     if let Some(loc) = loc {
         file = Some(loc.file.as_ref().unwrap().to_string());
@@ -76,25 +109,211 @@ fn extract_frame(pc: u64, context: &DebugContext) -> Frame {
     };
     let func = sprint_fun(&mut frame_iter);
 
-    let symbol = Symbol {
+    Symbol {
         name: Some(func),
         line,
         file,
-    };
-    let mut frame = Frame::default();
-    frame.stacks.push(symbol);
-    frame
+    }
+}
+
+// `None` on an out-of-range register number, so a malformed CFI entry can't
+// panic the signal handler; callers abort the unwind in that case.
+fn read_register(registers: &[u64], register: addr2line::gimli::Register) -> Option<u64> {
+    registers.get(register.0 as usize).copied()
+}
+
+fn write_register(
+    registers: &mut [u64],
+    register: addr2line::gimli::Register,
+    value: u64,
+) -> Option<()> {
+    *registers.get_mut(register.0 as usize)? = value;
+    Some(())
+}
+
+// Walks the CFI in `debug_frame` starting from the sampled `pc`, reconstructing
+// each caller's registers from the current `AsmMachine` state. Fills `buffer`
+// with the recovered `pc` values, innermost (sampled) frame first, and
+// returns how many entries were written. The sampled `pc` is stored as is;
+// every caller entry after it is already adjusted so it points inside the
+// call instruction rather than just past it.
+//
+// No heap allocation: `buffer`, `unwind_ctx` and `registers` are all
+// pre-allocated once on the `Profiler` and reused by every sample; register
+// snapshots within a walk are plain `[u64; GP_REGISTERS]` copies, not `Vec`
+// clones.
+//
+// `machine` is a raw pointer rather than `&AsmMachine`: reading a caller's
+// stack slot goes through `Memory::load64`, which ckb-vm defines on `&mut
+// self` (memory access can fault in pages), so touching it needs a mutable
+// reborrow. We never hold a `&AsmMachine`/`&mut AsmMachine` across more than
+// one expression here — each access re-derives its own narrowly-scoped
+// reference straight from the pointer — so a shared and a mutable borrow of
+// the live machine are never alive at once. That's sound because SIGPROF is
+// delivered synchronously to the thread currently running `machine`: the
+// interrupted run loop is suspended for this whole function call, so
+// nothing else can be touching it while we do.
+fn unwind_stack(
+    pc: u64,
+    machine: *mut AsmMachine,
+    context: &DebugContext,
+    unwind_ctx: &mut Addr2LineUnwindContext,
+    registers: &mut [u64; GP_REGISTERS],
+    buffer: &mut StackBuffer,
+) -> usize {
+    use addr2line::gimli::{CfaRule, RegisterRule, UnwindSection};
+
+    buffer[0] = pc;
+    let mut len = 1;
+
+    registers.copy_from_slice(unsafe { (*machine).machine.registers() });
+    let bases = addr2line::gimli::BaseAddresses::default();
+    let mut cur_pc = pc;
+    // 0 means "no caller frame unwound yet"; a real CFA is never 0 (checked
+    // below), so it doubles as "no monotonicity check on the first frame".
+    let mut prev_cfa = 0u64;
+
+    'walk: while len < MAX_DEPTH {
+        let row = match context.debug_frame.unwind_info_for_address(
+            &bases,
+            unwind_ctx,
+            cur_pc,
+            addr2line::gimli::DebugFrame::cie_from_offset,
+        ) {
+            Ok(row) => row,
+            Err(_) => break,
+        };
+
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                match read_register(&registers[..], *register) {
+                    Some(value) => (value as i64 + offset) as u64,
+                    // Register number out of range for a malformed CFI
+                    // entry: there's nothing sane left to unwind from.
+                    None => break 'walk,
+                }
+            }
+            CfaRule::Expression(_) => break,
+        };
+        if cfa == 0 {
+            break;
+        }
+        // The CFA must strictly advance outward frame over frame; one that
+        // doesn't means the CFI (or the stack it describes) is corrupt, and
+        // continuing would just loop up to MAX_DEPTH on a stuck unwind.
+        if prev_cfa != 0 && cfa <= prev_cfa {
+            break;
+        }
+        prev_cfa = cfa;
+
+        // A plain array copy (stack memory), not a `Vec` clone.
+        let mut new_registers = *registers;
+        for &(register, ref rule) in row.registers() {
+            let value = match rule {
+                RegisterRule::Undefined => continue,
+                RegisterRule::SameValue => match read_register(&registers[..], register) {
+                    Some(value) => value,
+                    None => break 'walk,
+                },
+                RegisterRule::Register(other) => match read_register(&registers[..], *other) {
+                    Some(value) => value,
+                    None => break 'walk,
+                },
+                RegisterRule::Offset(offset) => {
+                    let addr = (cfa as i64 + offset) as u64;
+                    // Scoped mutable reborrow — see the safety comment on
+                    // `unwind_stack`.
+                    match unsafe { (*machine).machine.memory_mut().load64(&addr) } {
+                        Ok(value) => value,
+                        // An unreadable CFA slot means the rest of this
+                        // frame's registers can't be trusted either; abort
+                        // the whole unwind rather than recording a bogus
+                        // frame and re-looping on the same `pc`.
+                        Err(_) => break 'walk,
+                    }
+                }
+                _ => continue,
+            };
+            if write_register(&mut new_registers[..], register, value).is_none() {
+                break 'walk;
+            }
+        }
+        if write_register(
+            &mut new_registers[..],
+            addr2line::gimli::Register(RISCV_DWARF_SP),
+            cfa,
+        )
+        .is_none()
+        {
+            break 'walk;
+        }
+
+        let ra = match read_register(&new_registers[..], addr2line::gimli::Register(RISCV_DWARF_RA))
+        {
+            Some(value) => value,
+            None => break 'walk,
+        };
+        if ra == 0 {
+            break;
+        }
+
+        *registers = new_registers;
+        // Step back one byte so the next lookup lands inside the call
+        // instruction instead of at the following one.
+        cur_pc = ra - 1;
+        buffer[len] = cur_pc;
+        len += 1;
+    }
+
+    len
+}
+
+// Builds a symbolized `Report` from raw address stacks. Never called from
+// the signal handler, since `extract_symbol` allocates and walks DWARF
+// data; used both when stopping the profiler and when a `ProfilerGuard`
+// peeks at progress via `report()`.
+fn symbolize(
+    samples: impl IntoIterator<Item = (Vec<u64>, Vec<String>, isize)>,
+    context: &DebugContext,
+) -> Report {
+    let mut report = Report::default();
+    for (stack, regions, count) in samples {
+        let mut frame = Frame {
+            regions,
+            ..Frame::default()
+        };
+        for pc in stack {
+            frame.stacks.push(extract_symbol(pc, context));
+        }
+        *report.data.entry(frame).or_insert(0) += count;
+    }
+    report
 }
 
 extern "C" fn perf_signal_handler(_signal: c_int) {
-    let mut profiler = PROFILER.lock().expect("Mutex lock failure");
+    // A try-lock keeps this async-signal-safe-ish: if the main thread is
+    // concurrently starting/stopping the profiler, drop the sample instead
+    // of blocking inside the handler.
+    let mut profiler = match PROFILER.try_lock() {
+        Ok(profiler) => profiler,
+        Err(_) => return,
+    };
     if let Some(profiler) = profiler.deref_mut() {
-        let machine = unsafe { &*(profiler.machine as *const AsmMachine) as &AsmMachine };
+        let machine = profiler.machine as *mut AsmMachine;
 
-        let pc = *machine.machine.pc();
-        let frame = extract_frame(pc, &profiler.context);
-
-        profiler.report.record(&frame);
+        // Scoped read — see the safety comment on `unwind_stack`.
+        let pc = unsafe { *(*machine).machine.pc() };
+        let len = unwind_stack(
+            pc,
+            machine,
+            &profiler.context,
+            &mut profiler.unwind_ctx,
+            &mut profiler.unwind_registers,
+            &mut profiler.scratch,
+        );
+        profiler
+            .collector
+            .record(&profiler.scratch[..len], &profiler.regions);
     }
 }
 
@@ -140,11 +359,84 @@ fn build_context(program: &Bytes) -> Result<DebugContext, String> {
     })
 }
 
-pub fn start_profiler(
+/// RAII handle for a running profiler, modeled on pprof-rs's `ProfilerGuard`.
+/// Dropping it flushes the collected `Report` to `fname` and uninstalls the
+/// `SIGPROF` handler, so a forgotten `stop_profiler` call can no longer leak
+/// a live signal handler. `report()` gives programmatic access to the data
+/// without waiting for (or forcing) that teardown.
+pub struct ProfilerGuard {
+    _private: (),
+}
+
+impl ProfilerGuard {
+    /// Symbolizes the samples collected so far, without stopping the
+    /// profiler.
+    pub fn report(&self) -> Result<Report, String> {
+        let profiler_guard = PROFILER.lock().expect("Mutex lock failure");
+        let profiler = profiler_guard
+            .as_ref()
+            .ok_or_else(|| "Profiler not started!".to_string())?;
+        Ok(symbolize(
+            profiler.collector.snapshot(),
+            &profiler.context,
+        ))
+    }
+
+    /// Annotates every sample taken until the returned guard drops with
+    /// `name`, so sampled cost can be attributed to a logical phase (syscall
+    /// handling, a contract entry point, a verification pass, ...) that the
+    /// DWARF symbols alone can't reveal. Regions nest: the active region
+    /// stack is recorded as pprof sample labels.
+    pub fn enter_region(&self, name: &str) -> RegionGuard {
+        let mut profiler_guard = PROFILER.lock().expect("Mutex lock failure");
+        if let Some(profiler) = profiler_guard.as_mut() {
+            profiler.regions.push(name.to_string());
+        }
+        RegionGuard(())
+    }
+}
+
+impl Drop for ProfilerGuard {
+    fn drop(&mut self) {
+        if let Err(e) = stop_profiler() {
+            eprintln!("ckb-vm-signal-profiler: failed to stop profiler: {}", e);
+        }
+    }
+}
+
+/// RAII marker for a user-defined region opened by `ProfilerGuard::enter_region`.
+/// Dropping it (typically at end of scope) pops the region back off, so
+/// samples taken afterwards no longer carry its name as a label.
+pub struct RegionGuard(());
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        let mut profiler_guard = PROFILER.lock().expect("Mutex lock failure");
+        if let Some(profiler) = profiler_guard.as_mut() {
+            profiler.regions.pop();
+        }
+    }
+}
+
+/// Starts sampling `machine` at `frequency_per_sec` and returns a guard that
+/// flushes to `fname` in `format` once dropped.
+pub fn start(
     fname: &str,
     machine: &Pin<Box<AsmMachine>>,
     program: &Bytes,
     frequency_per_sec: i32,
+    format: OutputFormat,
+) -> Result<ProfilerGuard, String> {
+    start_profiler(fname, machine, program, frequency_per_sec, format)?;
+    Ok(ProfilerGuard { _private: () })
+}
+
+fn start_profiler(
+    fname: &str,
+    machine: &Pin<Box<AsmMachine>>,
+    program: &Bytes,
+    frequency_per_sec: i32,
+    format: OutputFormat,
 ) -> Result<(), String> {
     if is_profiler_started() {
         return Err("Profiler already started!".to_string());
@@ -164,10 +456,15 @@ pub fn start_profiler(
 
     let profiler = Profiler {
         fname: fname.to_string(),
+        format,
         machine: machine.deref() as *const AsmMachine as usize,
         context,
         timer: Timer::new(frequency_per_sec),
-        report: Report::default(),
+        collector: Collector::default(),
+        scratch: [0; MAX_DEPTH],
+        unwind_ctx: Addr2LineUnwindContext::new(),
+        unwind_registers: [0; GP_REGISTERS],
+        regions: Vec::new(),
     };
 
     *(PROFILER.lock().expect("Mutex lock failure")) = Some(profiler);
@@ -175,30 +472,86 @@ pub fn start_profiler(
     Ok(())
 }
 
-pub fn stop_profiler() -> Result<(), String> {
-    let mut profiler = PROFILER.lock().expect("Mutex lock failure");
-    if profiler.is_none() {
-        return Err("Profiler not started!".to_string());
-    }
-    // save profiled data
-    let inner_profiler = profiler.deref().as_ref().unwrap();
-    let fname = &inner_profiler.fname;
+fn stop_profiler() -> Result<(), String> {
+    let mut profiler_guard = PROFILER.lock().expect("Mutex lock failure");
+    let inner_profiler = match profiler_guard.as_mut() {
+        Some(profiler) => profiler,
+        None => return Err("Profiler not started!".to_string()),
+    };
+
+    // save profiled data, symbolizing the collected raw stacks now that
+    // we're outside the signal handler
+    let fname = inner_profiler.fname.clone();
     let timing = inner_profiler.timer.timing();
-    let profile_data = inner_profiler
-        .report
-        .pprof(timing)
-        .expect("pprof serialization");
-    let data = profile_data
-        .write_to_bytes()
-        .expect("protobuf serialization");
-    fs::write(fname, data).expect("write");
+    let collector = std::mem::take(&mut inner_profiler.collector);
+    let report = symbolize(collector.into_samples(), &inner_profiler.context);
+    match inner_profiler.format {
+        OutputFormat::Pprof => {
+            let profile_data = report.pprof(timing).expect("pprof serialization");
+            let data = profile_data
+                .write_to_bytes()
+                .expect("protobuf serialization");
+            fs::write(fname, data).expect("write");
+        }
+        OutputFormat::Flamegraph => {
+            let file = fs::File::create(&fname).expect("create output file");
+            report.flamegraph(file).expect("flamegraph render");
+        }
+    }
 
     // uninstall signal handler
     let handler = signal::SigHandler::SigIgn;
     unsafe { signal::signal(signal::SIGPROF, handler) }
         .map_err(|e| format!("sigaction uninstall error: {}", e))?;
 
-    *profiler = None;
+    *profiler_guard = None;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_register_is_none_out_of_range() {
+        let registers = [0u64; GP_REGISTERS];
+        assert_eq!(
+            read_register(&registers, addr2line::gimli::Register(GP_REGISTERS as u16)),
+            None
+        );
+    }
+
+    #[test]
+    fn read_register_returns_the_value_in_range() {
+        let mut registers = [0u64; GP_REGISTERS];
+        registers[RISCV_DWARF_SP as usize] = 42;
+        assert_eq!(
+            read_register(&registers, addr2line::gimli::Register(RISCV_DWARF_SP)),
+            Some(42)
+        );
+    }
+
+    #[test]
+    fn write_register_is_none_out_of_range() {
+        let mut registers = [0u64; GP_REGISTERS];
+        assert_eq!(
+            write_register(
+                &mut registers,
+                addr2line::gimli::Register(GP_REGISTERS as u16),
+                1,
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn write_register_writes_the_value_in_range() {
+        let mut registers = [0u64; GP_REGISTERS];
+        assert_eq!(
+            write_register(&mut registers, addr2line::gimli::Register(RISCV_DWARF_RA), 7),
+            Some(())
+        );
+        assert_eq!(registers[RISCV_DWARF_RA as usize], 7);
+    }
+}
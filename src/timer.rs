@@ -0,0 +1,68 @@
+use std::ptr;
+use std::time::{Duration, Instant};
+
+/// Drives periodic `SIGPROF` delivery at `frequency_per_sec` via `setitimer`,
+/// and tracks wall-clock duration for the pprof duration field.
+pub struct Timer {
+    started_at: Instant,
+}
+
+impl Timer {
+    pub fn new(frequency_per_sec: i32) -> Timer {
+        set_timer(interval_for(frequency_per_sec));
+
+        Timer {
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Wall-clock time elapsed since the timer was started.
+    pub fn timing(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // Stop delivering SIGPROF before the profiler tears down the handler.
+        set_timer(Duration::from_secs(0));
+    }
+}
+
+fn interval_for(frequency_per_sec: i32) -> Duration {
+    Duration::from_secs(1) / frequency_per_sec.max(1) as u32
+}
+
+fn set_timer(interval: Duration) {
+    let value = libc::itimerval {
+        it_interval: to_timeval(interval),
+        it_value: to_timeval(interval),
+    };
+    unsafe {
+        libc::setitimer(libc::ITIMER_PROF, &value, ptr::null_mut());
+    }
+}
+
+fn to_timeval(duration: Duration) -> libc::timeval {
+    libc::timeval {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_usec: duration.subsec_micros() as libc::suseconds_t,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_for_divides_a_second_by_the_frequency() {
+        assert_eq!(interval_for(4), Duration::from_millis(250));
+        assert_eq!(interval_for(1), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn interval_for_treats_non_positive_frequency_as_one() {
+        assert_eq!(interval_for(0), Duration::from_secs(1));
+        assert_eq!(interval_for(-100), Duration::from_secs(1));
+    }
+}